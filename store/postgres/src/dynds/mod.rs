@@ -0,0 +1,84 @@
+mod private;
+
+use std::collections::BTreeMap;
+
+use diesel::PgConnection;
+
+use graph::{
+    components::store::StoredDynamicDataSource,
+    prelude::{BlockNumber, StoreError},
+};
+
+use crate::primary::Namespace;
+
+use private::DataSourcesTable;
+
+/// The persisted dynamic data sources of a subgraph, namespaced to its own
+/// `data_sources$` table. Wraps [`private::DataSourcesTable`], which does the
+/// actual SQL, so that callers outside this module never depend on its
+/// private, diesel-dynamic-schema-backed representation.
+#[derive(Debug)]
+pub(crate) struct DataSourcesStore {
+    table: DataSourcesTable,
+}
+
+impl DataSourcesStore {
+    pub(crate) fn new(namespace: Namespace) -> Self {
+        DataSourcesStore {
+            table: DataSourcesTable::new(namespace),
+        }
+    }
+
+    pub(crate) fn as_ddl(&self) -> String {
+        self.table.as_ddl()
+    }
+
+    pub(crate) fn load(
+        &self,
+        conn: &mut PgConnection,
+        block: BlockNumber,
+    ) -> Result<Vec<StoredDynamicDataSource>, StoreError> {
+        self.table.load(conn, block)
+    }
+
+    /// Insert `data_sources`, returning the `causality_region` assigned to
+    /// each offchain entry, keyed by its index in `data_sources` - the caller
+    /// uses this to correlate its in-memory offchain data sources with the
+    /// regions Postgres assigned them, without a follow-up `load`.
+    pub(crate) fn insert(
+        &self,
+        conn: &mut PgConnection,
+        data_sources: &[StoredDynamicDataSource],
+        block: BlockNumber,
+    ) -> Result<BTreeMap<usize, i32>, StoreError> {
+        let inserted = self.table.insert(conn, data_sources, block)?;
+
+        Ok(inserted
+            .into_iter()
+            .enumerate()
+            .filter(|(idx, _)| data_sources[*idx].is_offchain)
+            .map(|(idx, (_vid, causality_region))| (idx, causality_region))
+            .collect())
+    }
+
+    pub(crate) fn revert(&self, conn: &mut PgConnection, block: BlockNumber) -> Result<(), StoreError> {
+        self.table.revert(conn, block)
+    }
+
+    pub(crate) fn copy_to(
+        &self,
+        conn: &mut PgConnection,
+        dst: &DataSourcesStore,
+        target_block: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        self.table.copy_to(conn, &dst.table, target_block)
+    }
+
+    pub(crate) fn remove_offchain(
+        &self,
+        conn: &mut PgConnection,
+        data_sources: &[StoredDynamicDataSource],
+    ) -> Result<(), StoreError> {
+        self.table.remove_offchain(conn, data_sources)
+    }
+}
@@ -4,7 +4,8 @@ use diesel::{
     pg::types::sql_types,
     sql_query,
     sql_types::{Binary, Integer, Jsonb, Nullable},
-    PgConnection, QueryDsl, RunQueryDsl,
+    BoolExpressionMethods, Connection as _, ExpressionMethods, PgConnection, PgExpressionMethods,
+    QueryDsl, QueryableByName, RunQueryDsl,
 };
 
 use graph::{
@@ -13,10 +14,23 @@ use graph::{
     prelude::{serde_json, BlockNumber, StoreError},
 };
 
+use crate::block_range::{
+    empty_range, BlockRangeColumns, BlockRangeExpressionMethods as _, BlockRangeTable, CopyOutcome,
+    DynColumn, DynTable,
+};
 use crate::primary::Namespace;
 
-type DynTable = diesel_dynamic_schema::Table<String, Namespace>;
-type DynColumn<ST> = diesel_dynamic_schema::Column<DynTable, &'static str, ST>;
+/// The `(vid, causality_region)` of a row just inserted into a
+/// `data_sources$` table. Offchain data sources get their `causality_region`
+/// assigned from an identity sequence in the database, so this is the only
+/// way a caller learns which region was assigned without a follow-up `load`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, QueryableByName)]
+struct InsertedDataSource {
+    #[diesel(sql_type = Integer)]
+    vid: i32,
+    #[diesel(sql_type = Integer)]
+    causality_region: i32,
+}
 
 #[derive(Debug)]
 pub(crate) struct DataSourcesTable {
@@ -29,24 +43,40 @@ pub(crate) struct DataSourcesTable {
     manifest_idx: DynColumn<Integer>,
     param: DynColumn<Nullable<Binary>>,
     context: DynColumn<Nullable<Jsonb>>,
+    /// The generic block-range versioning primitives (`revert`, `copy_to`)
+    /// shared with other versioned tables; see [`crate::block_range`].
+    range: BlockRangeTable<DataSourcesTable>,
+}
+
+impl BlockRangeColumns for DataSourcesTable {
+    const TABLE_NAME: &'static str = Self::TABLE_NAME;
+
+    const EXTRA_COLUMNS: &'static [&'static str] =
+        &["causality_region", "manifest_idx", "parent", "id", "param", "context"];
 }
 
 impl DataSourcesTable {
     const TABLE_NAME: &'static str = "data_sources$";
 
+    // Postgres has a limit of 65535 bind parameters per statement; each row
+    // binds at most 5 of them, so this keeps well clear of that limit while
+    // still collapsing a block's data sources into O(1) statements.
+    const INSERT_CHUNK_SIZE: usize = 5_000;
+
     pub(crate) fn new(namespace: Namespace) -> Self {
         let table =
             diesel_dynamic_schema::schema(namespace.clone()).table(Self::TABLE_NAME.to_string());
 
         DataSourcesTable {
             qname: format!("{}.{}", namespace, Self::TABLE_NAME),
-            namespace,
+            range: BlockRangeTable::new(namespace.clone()),
             vid: table.column("vid"),
             block_range: table.column("block_range"),
             causality_region: table.column("causality_region"),
             manifest_idx: table.column("manifest_idx"),
             param: table.column("param"),
             context: table.column("context"),
+            namespace,
             table,
         }
     }
@@ -90,7 +120,7 @@ impl DataSourcesTable {
         let tuples = self
             .table
             .clone()
-            .filter(diesel::dsl::sql("block_range @> ").bind::<Integer, _>(block))
+            .filter(self.block_range.clone().contains(block))
             .select((
                 &self.block_range,
                 &self.manifest_idx,
@@ -132,60 +162,132 @@ impl DataSourcesTable {
         Ok(dses)
     }
 
+    /// Insert `data_sources`, returning the `(vid, causality_region)` that
+    /// was assigned to each inserted row, in the same order as
+    /// `data_sources` - callers rely on this to positionally correlate an
+    /// in-memory offchain data source with its persisted causality region.
+    ///
+    /// Onchain and offchain data sources are inserted as two separate
+    /// multi-row `insert`s (offchain rows omit `causality_region`, which is
+    /// always assigned by the database), each batched in groups of at most
+    /// [`Self::INSERT_CHUNK_SIZE`] rows to stay under Postgres' bind
+    /// parameter limit, rather than one `insert` per row. The original
+    /// `data_sources` order is restored afterwards by writing each group's
+    /// results back by their index in `data_sources`.
     pub(crate) fn insert(
         &self,
         conn: &mut PgConnection,
         data_sources: &[StoredDynamicDataSource],
         block: BlockNumber,
-    ) -> Result<usize, StoreError> {
-        let mut inserted_total = 0;
-
+    ) -> Result<Vec<(i32, i32)>, StoreError> {
         for ds in data_sources {
-            let StoredDynamicDataSource {
-                manifest_idx,
-                param,
-                context,
-                creation_block,
-                is_offchain,
-            } = ds;
-
-            if creation_block != &Some(block) {
+            if ds.creation_block != Some(block) {
                 return Err(constraint_violation!(
                     "mismatching creation blocks `{:?}` and `{}`",
-                    creation_block,
+                    ds.creation_block,
                     block
                 ));
             }
+        }
 
-            // Offchain data sources have a unique causality region assigned from a sequence in the
-            // database, while onchain data sources always have causality region 0.
-            let query = match is_offchain {
-                false => format!(
-                    "insert into {}(block_range, manifest_idx, param, context, causality_region) \
-                            values (int4range($1, null), $2, $3, $4, $5)",
-                    self.qname
-                ),
+        let (onchain, offchain): (Vec<_>, Vec<_>) =
+            data_sources.iter().enumerate().partition(|(_, ds)| !ds.is_offchain);
 
-                true => format!(
-                    "insert into {}(block_range, manifest_idx, param, context) \
-                            values (int4range($1, null), $2, $3, $4)",
-                    self.qname
-                ),
-            };
+        let mut inserted = vec![None; data_sources.len()];
+        for group in onchain.chunks(Self::INSERT_CHUNK_SIZE) {
+            self.insert_group(conn, group, false, &mut inserted)?;
+        }
+        for group in offchain.chunks(Self::INSERT_CHUNK_SIZE) {
+            self.insert_group(conn, group, true, &mut inserted)?;
+        }
+
+        Ok(inserted
+            .into_iter()
+            .map(|row| row.expect("every data source is inserted exactly once"))
+            .collect())
+    }
+
+    /// Insert a single group of data sources, all either onchain or
+    /// offchain, as one multi-row `insert ... values (...), (...), ...`
+    /// statement, writing the `(vid, causality_region)` assigned to each row
+    /// into `out` at that row's original index in `data_sources`.
+    fn insert_group(
+        &self,
+        conn: &mut PgConnection,
+        group: &[(usize, &StoredDynamicDataSource)],
+        is_offchain: bool,
+        out: &mut [Option<(i32, i32)>],
+    ) -> Result<(), StoreError> {
+        if group.is_empty() {
+            return Ok(());
+        }
+
+        // Offchain data sources have a unique causality region assigned from a sequence in the
+        // database, while onchain data sources always have causality region 0, which we bind
+        // explicitly alongside the other columns.
+        let cols_per_row = if is_offchain { 4 } else { 5 };
+        let values = (0..group.len())
+            .map(|row| {
+                let p = |col: usize| format!("${}", row * cols_per_row + col + 1);
+                if is_offchain {
+                    format!("(int4range({}, null), {}, {}, {})", p(0), p(1), p(2), p(3))
+                } else {
+                    format!(
+                        "(int4range({}, null), {}, {}, {}, {})",
+                        p(0),
+                        p(1),
+                        p(2),
+                        p(3),
+                        p(4)
+                    )
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let query = if is_offchain {
+            format!(
+                "insert into {}(block_range, manifest_idx, param, context) values {} \
+                        returning vid, causality_region",
+                self.qname, values
+            )
+        } else {
+            format!(
+                "insert into {}(block_range, manifest_idx, param, context, causality_region) \
+                        values {} returning vid, causality_region",
+                self.qname, values
+            )
+        };
+
+        let mut query = sql_query(query).into_boxed::<diesel::pg::Pg>();
+        for (_, ds) in group {
+            let StoredDynamicDataSource {
+                manifest_idx,
+                param,
+                context,
+                creation_block,
+                ..
+            } = ds;
 
-            let query = sql_query(query)
+            query = query
                 .bind::<Nullable<Integer>, _>(creation_block)
                 .bind::<Integer, _>(*manifest_idx as i32)
                 .bind::<Nullable<Binary>, _>(param.as_ref().map(|p| &**p))
                 .bind::<Nullable<Jsonb>, _>(context);
+            if !is_offchain {
+                query = query.bind::<Integer, _>(0);
+            }
+        }
 
-            inserted_total += match is_offchain {
-                false => query.bind::<Integer, _>(0).execute(conn)?,
-                true => query.execute(conn)?,
-            };
+        // Postgres preserves row order between a multi-row `VALUES` list and the rows produced
+        // by its `RETURNING` clause, so the nth result here corresponds to the nth entry of
+        // `group`.
+        let rows = query.get_results::<InsertedDataSource>(conn)?;
+        for ((idx, _), row) in group.iter().zip(rows) {
+            out[*idx] = Some((row.vid, row.causality_region));
         }
 
-        Ok(inserted_total)
+        Ok(())
     }
 
     pub(crate) fn revert(
@@ -193,14 +295,7 @@ impl DataSourcesTable {
         conn: &mut PgConnection,
         block: BlockNumber,
     ) -> Result<(), StoreError> {
-        // Use `@>` to leverage the gist index.
-        // This assumes all ranges are of the form [x, +inf).
-        let query = format!(
-            "delete from {} where block_range @> $1 and lower(block_range) = $1",
-            self.qname
-        );
-        sql_query(query).bind::<Integer, _>(block).execute(conn)?;
-        Ok(())
+        self.range.revert(conn, block)
     }
 
     /// Copy the dynamic data sources from `self` to `dst`. All data sources that
@@ -211,38 +306,37 @@ impl DataSourcesTable {
         dst: &DataSourcesTable,
         target_block: BlockNumber,
     ) -> Result<usize, StoreError> {
-        // Check if there are any data sources for dst which indicates we already copied
-        let count = dst.table.clone().count().get_result::<i64>(conn)?;
-        if count > 0 {
-            return Ok(count as usize);
-        }
-
-        let query = format!(
-            "\
-            insert into {dst}(block_range, causality_region, manifest_idx, parent, id, param, context)
-            select case
-                when upper(e.block_range) <= $1 then e.block_range
-                else int4range(lower(e.block_range), null)
-            end,
-            e.causality_region, e.manifest_idx, e.parent, e.id, e.param, e.context
-            from {src} e
-            where lower(e.block_range) <= $1
-            ",
-            src = self.qname,
-            dst = dst.qname
-        );
-
-        let count = sql_query(&query)
-            .bind::<Integer, _>(target_block)
-            .execute(conn)?;
-
-        // Test that both tables have the same contents.
-        debug_assert!(
-            self.load(conn, target_block).map_err(|e| e.to_string())
-                == dst.load(conn, target_block).map_err(|e| e.to_string())
-        );
+        // Run the copy and the sequence restart below as a single transaction (a savepoint, if
+        // `conn` is already inside one), so that they commit or roll back together. This takes
+        // no lock on `dst`: it does not keep a concurrent offchain `insert` into `dst` from
+        // drawing a region from the sequence before the restart.
+        conn.transaction(|conn| {
+            let outcome = self.range.copy_to(conn, &dst.range, target_block)?;
+
+            // Only a fresh copy needs the sequence restart below: `copy_to` carries over explicit
+            // `causality_region` values, but explicit inserts don't advance an identity sequence,
+            // so without this the first offchain `insert` into `dst` would let Postgres pick
+            // `nextval` starting from 1 again and collide with a region that was already copied.
+            // A no-op `copy_to` didn't touch `dst`, so there's nothing to restart past.
+            if let CopyOutcome::Copied(_) = outcome {
+                // `restart with` only accepts a literal, not a subquery, so advance the owned
+                // sequence directly with `setval`.
+                let restart_sequence = format!(
+                    "select setval(pg_get_serial_sequence('{dst}', 'causality_region'), \
+                        (select coalesce(max(causality_region), 0) + 1 from {dst}), false)",
+                    dst = dst.qname
+                );
+                sql_query(restart_sequence).execute(conn)?;
+
+                // Test that both tables have the same contents.
+                debug_assert!(
+                    self.load(conn, target_block).map_err(|e| e.to_string())
+                        == dst.load(conn, target_block).map_err(|e| e.to_string())
+                );
+            }
 
-        Ok(count)
+            Ok(outcome.row_count())
+        })
     }
 
     // Remove offchain data sources by checking for equality. Their range will be set to the empty range.
@@ -266,21 +360,22 @@ impl DataSourcesTable {
                 ));
             }
 
-            let query = format!(
-                "update {} set block_range = 'empty'::int4range \
-                 where manifest_idx = $1
-                    and param is not distinct from $2
-                    and context is not distinct from $3
-                    and lower(block_range) is not distinct from $4",
-                self.qname
-            );
-
-            let count = sql_query(query)
-                .bind::<Integer, _>(*manifest_idx as i32)
-                .bind::<Nullable<Binary>, _>(param.as_ref().map(|p| &**p))
-                .bind::<Nullable<Jsonb>, _>(context)
-                .bind::<Nullable<Integer>, _>(creation_block)
-                .execute(conn)?;
+            let count = diesel::update(self.table.clone().filter(
+                self.manifest_idx.clone().eq(*manifest_idx as i32).and(
+                    self.param
+                        .clone()
+                        .is_not_distinct_from(param.as_ref().map(|p| &**p))
+                        .and(self.context.clone().is_not_distinct_from(context))
+                        .and(
+                            self.block_range
+                                .clone()
+                                .lower()
+                                .is_not_distinct_from(*creation_block),
+                        ),
+                ),
+            ))
+            .set(self.block_range.clone().eq(empty_range()))
+            .execute(conn)?;
 
             if count > 1 {
                 // Data source deduplication enforces this invariant.
@@ -296,3 +391,54 @@ impl DataSourcesTable {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use diesel::{connection::SimpleConnection as _, Connection as _};
+
+    use super::*;
+
+    // Talks to a real Postgres database, gated behind the same env var the rest of the store's
+    // test suite uses.
+    fn conn() -> PgConnection {
+        let url = std::env::var("THEGRAPH_STORE_POSTGRES_DIESEL_URL")
+            .expect("THEGRAPH_STORE_POSTGRES_DIESEL_URL must be set to run dynds tests");
+        PgConnection::establish(&url).expect("failed to connect to test database")
+    }
+
+    fn offchain_ds(manifest_idx: u32, block: BlockNumber) -> StoredDynamicDataSource {
+        StoredDynamicDataSource {
+            manifest_idx,
+            param: None,
+            context: None,
+            creation_block: Some(block),
+            is_offchain: true,
+        }
+    }
+
+    #[test]
+    fn copy_to_advances_causality_region_past_copied_regions() {
+        let conn = &mut conn();
+        conn.test_transaction::<_, StoreError, _>(|conn| {
+            let src = DataSourcesTable::new(Namespace::new("sgd_dynds_copy_src".to_string()).unwrap());
+            conn.batch_execute(&src.as_ddl())?;
+            let dst = DataSourcesTable::new(Namespace::new("sgd_dynds_copy_dst".to_string()).unwrap());
+            conn.batch_execute(&dst.as_ddl())?;
+
+            let copied_region = src.insert(conn, &[offchain_ds(0, 1)], 1)?[0].1;
+
+            src.copy_to(conn, &dst, 1)?;
+
+            let new_region = dst.insert(conn, &[offchain_ds(0, 2)], 2)?[0].1;
+
+            assert!(
+                new_region > copied_region,
+                "new causality region {} should be greater than the copied region {}",
+                new_region,
+                copied_region
+            );
+
+            Ok(())
+        });
+    }
+}